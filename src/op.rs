@@ -0,0 +1,39 @@
+pub use crate::tensor::{ComputeContext, GradientContext};
+use crate::ops::schema::OpSchema;
+use crate::tensor::Tensor;
+use crate::Float;
+use crate::Graph;
+
+/// A node's forward/backward computation.
+pub trait Op<T: Float> {
+    /// Runs the forward computation.
+    fn compute(&self, ctx: &mut ComputeContext<T>);
+
+    /// Computes the gradients of this op's inputs from `ctx.output_grad()`.
+    fn grad(&self, ctx: &mut GradientContext<T>);
+
+    /// Declares this op's input arity and in-place aliasing permissions.
+    /// Defaults to unconstrained arity and no aliasing, so ops that don't
+    /// override this keep compiling and behave exactly as before `OpSchema`
+    /// was introduced.
+    fn schema(&self) -> OpSchema {
+        OpSchema::default()
+    }
+}
+
+/// Builds `op` from `inputs`, validating `inputs.len()` against `op.schema()`
+/// first. Centralizes the arity check at graph-construction time for the
+/// op-building call sites within this crate, instead of repeating
+/// `self.schema().check_arity(...)` inside every one of those ops'
+/// `compute()`. Ops constructed elsewhere without this helper (e.g. by
+/// operator-overload glue on `Tensor`) still validate their own arity at the
+/// top of `compute`.
+pub fn build_checked<'a, 'b: 'a, T: Float, O: Op<T> + 'static>(
+    graph: &'b Graph<T>,
+    inputs: &[&Tensor<'a, 'b, T>],
+    op: O,
+) -> Tensor<'a, 'b, T> {
+    op.schema()
+        .check_arity(std::any::type_name::<O>(), inputs.len());
+    Tensor::builder().set_ro_inputs(inputs).build(graph, op)
+}
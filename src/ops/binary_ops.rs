@@ -1,3 +1,4 @@
+use super::schema::OpSchema;
 use crate::ndarray_ext::{NdArray, NdArrayView};
 use crate::op;
 use crate::tensor::Tensor;
@@ -5,9 +6,9 @@ use crate::Float;
 use crate::Graph;
 /// Implement +, -, *, / operators for Tensor
 /// +=, -=, *=, /= are provided as methods of c.inplace_*.
-/// *=, /= don't propagate gradients.
+/// += and -= (`InplaceAddOp`/`InplaceSubOp`) do propagate gradients; *=, /=
+/// are plain buffer mutations and don't.
 use ndarray;
-use std::mem;
 
 pub struct AddOp;
 pub struct SubOp;
@@ -20,6 +21,10 @@ impl<T: Float> op::Op<T> for PreprocessBinOpGrad {
     // Computes x's gradient.
     // Involves reduction as necessary.
     // Inputs: [gy, target_shape]
+    //
+    // Arity is validated by callers at construction time (`op::build_checked`
+    // in `PreprocessBinOpGradGrad::grad` below, and an explicit `check_arity`
+    // in `preprocess_gy`), since this op is only ever built within this crate.
     fn compute(&self, ctx: &mut crate::op::ComputeContext<T>) {
         let gy = ctx.input(0);
         let x_shape_ = crate::ndarray_ext::as_shape(&ctx.input(1));
@@ -29,58 +34,117 @@ impl<T: Float> op::Op<T> for PreprocessBinOpGrad {
         if x_shape == gy_shape {
             // The case where forward path didn't cause broadcast.
             ctx.append_output_view(Ok(gy.clone()));
-        } else {
-            // Broadcast occurred. We need reduction of `gy`.
-            // First, handle the case where x is scalar.
-            let x_is_scalar = crate::ndarray_ext::is_scalar_shape(x_shape);
-            let x_shape = if x_is_scalar {
-                vec![1; gy_shape.len()]
-            } else {
-                x_shape.to_vec()
-            };
-            // Reduce each dim as necessary
-            let mut folded: Option<NdArray<T>> = None;
-            for (i, (x_axis, gy_axis)) in x_shape.iter().zip(gy_shape).enumerate() {
-                if x_axis < gy_axis {
-                    if *x_axis == 1 {
-                        // `fold_axis` squashes the axis automatically.
-                        let axis = ndarray::Axis(if x_is_scalar { 0 } else { i });
-                        let ret = match folded {
-                            Some(ref a) => a.fold_axis(axis.clone(), T::zero(), |&a, &b| a + b),
-                            None => gy.fold_axis(axis.clone(), T::zero(), |&a, &b| a + b),
-                        };
-                        if x_is_scalar {
-                            mem::swap(&mut folded, &mut Some(ret));
-                        } else {
-                            // Expands squashed axis.
-                            mem::swap(
-                                &mut folded,
-                                &mut Some(crate::ndarray_ext::expand_dims(ret, i)),
-                            );
-                        }
-                    } else {
-                        panic!("Incorrect gradient shape");
-                    }
-                }
-                // case of x_axis < gy_axis: unreachable
-                // case of x_axis == gy_axis: nothing to do
-            }
-            // TODO
-            ctx.append_output(Ok(folded.unwrap()));
-        };
+            return;
+        }
+
+        ctx.append_output(Ok(reduce_to_shape(&gy, x_shape)));
     }
 
     fn grad(&self, ctx: &mut crate::op::GradientContext<T>) {
-        let gx = Tensor::builder()
-            .set_ro_inputs(&[&ctx.output_grad(), &ctx.input(1)])
-            .build(ctx.graph(), PreprocessBinOpGradGrad);
+        let gx = op::build_checked(
+            ctx.graph(),
+            &[&ctx.output_grad(), &ctx.input(1)],
+            PreprocessBinOpGradGrad,
+        );
         ctx.set_input_grads(vec![Some(gx), None]);
     }
+
+    fn schema(&self) -> OpSchema {
+        // Inputs: [gy, target_shape]
+        OpSchema::exactly(2)
+    }
+}
+
+/// Reduces `gy` down to `x_shape` using NumPy/TensorFlow broadcasting-reduction
+/// semantics. Shapes are aligned from the trailing dimension: the shorter
+/// `x_shape` is treated as if left-padded with leading 1s up to `gy`'s rank.
+/// Every leading axis `x` doesn't have is summed away entirely, and every
+/// remaining axis where `x` is 1 but `gy` is >1 is summed while keeping the
+/// dim. A scalar `x_shape` collapses `gy` to a full sum. Callers must first
+/// check `x_shape == gy.shape()`, in which case no reduction is needed.
+fn reduce_to_shape<T: Float>(gy: &NdArrayView<T>, x_shape: &[usize]) -> NdArray<T> {
+    let gy_shape = gy.shape();
+    let x_is_scalar = crate::ndarray_ext::is_scalar_shape(x_shape);
+    let x_rank = if x_is_scalar { 0 } else { x_shape.len() };
+    let num_leading_axes = gy_shape.len() - x_rank;
+
+    // Sum away every leading axis that `x` doesn't have at all.
+    let mut reduced: NdArray<T> = gy.to_owned();
+    for _ in 0..num_leading_axes {
+        reduced = reduced.sum_axis(ndarray::Axis(0));
+    }
+
+    // Sum-reduce (keeping the dim) every remaining axis where `x` is 1 but
+    // `gy` is >1.
+    if !x_is_scalar {
+        for (i, &x_axis) in x_shape.iter().enumerate() {
+            if x_axis == 1 && reduced.shape()[i] != 1 {
+                let summed = reduced.sum_axis(ndarray::Axis(i));
+                reduced = crate::ndarray_ext::expand_dims(summed, i);
+            }
+        }
+    }
+
+    reduced
+}
+
+#[cfg(test)]
+mod reduce_to_shape_tests {
+    use super::*;
+    use ndarray::Array;
+
+    #[test]
+    fn sums_away_leading_rank_expansion() {
+        // x: [3,4], gy: [2,3,4] -> sum over the leading axis gy has but x doesn't.
+        let gy = Array::from_shape_fn((2, 3, 4), |(i, j, k)| (i * 12 + j * 4 + k) as f64).into_dyn();
+        let reduced = reduce_to_shape(&gy.view(), &[3, 4]);
+        assert_eq!(reduced.shape(), &[3, 4]);
+        assert_eq!(reduced, gy.sum_axis(ndarray::Axis(0)));
+    }
+
+    #[test]
+    fn sums_broadcast_axis_keeping_dim() {
+        // x: [1,4], gy: [3,4] -> sum over axis 0, dim kept (not dropped).
+        let gy = Array::from_shape_fn((3, 4), |(i, j)| (i * 4 + j) as f64).into_dyn();
+        let reduced = reduce_to_shape(&gy.view(), &[1, 4]);
+        assert_eq!(reduced.shape(), &[1, 4]);
+        for j in 0..4 {
+            let expected: f64 = (0..3).map(|i| gy[[i, j]]).sum();
+            assert_eq!(reduced[[0, j]], expected);
+        }
+    }
+
+    #[test]
+    fn scalar_x_collapses_to_full_sum() {
+        let gy = Array::from_shape_fn((2, 3), |(i, j)| (i * 3 + j + 1) as f64).into_dyn();
+        let reduced = reduce_to_shape(&gy.view(), &[]);
+        assert_eq!(reduced.shape(), &[] as &[usize]);
+        let total: f64 = gy.iter().sum();
+        assert_eq!(reduced[ndarray::IxDyn(&[])], total);
+    }
+
+    #[test]
+    fn mixed_rank_padding_and_axis_reduction() {
+        // x: [1,4], gy: [2,3,4] -> pad x to [1,1,4], sum the leading axis
+        // (rank expansion), then sum the remaining size-1 axis keeping its dim.
+        let gy = Array::from_shape_fn((2, 3, 4), |(i, j, k)| (i * 12 + j * 4 + k) as f64).into_dyn();
+        let reduced = reduce_to_shape(&gy.view(), &[1, 4]);
+        assert_eq!(reduced.shape(), &[1, 4]);
+        for k in 0..4 {
+            let expected: f64 = (0..2)
+                .flat_map(|i| (0..3).map(move |j| gy[[i, j, k]]))
+                .sum();
+            assert_eq!(reduced[[0, k]], expected);
+        }
+    }
 }
 
 // Do broadcast if necessary.
 // Inputs: [gy, target_shape]
 impl<T: Float> op::Op<T> for PreprocessBinOpGradGrad {
+    // Arity is validated by the caller at construction time
+    // (`op::build_checked` in `PreprocessBinOpGrad::grad`), since this op is
+    // only ever built within this crate.
     fn compute(&self, ctx: &mut crate::op::ComputeContext<T>) {
         let target_shape_ = ctx.input(1);
         let target_shape_ = crate::ndarray_ext::as_shape(&target_shape_);
@@ -112,15 +176,26 @@ impl<T: Float> op::Op<T> for PreprocessBinOpGradGrad {
     }
 
     fn grad(&self, ctx: &mut crate::op::GradientContext<T>) {
-        let gx = Tensor::builder()
-            .set_ro_inputs(&[&ctx.input(0), &ctx.output_grad()])
-            .build(ctx.graph(), PreprocessBinOpGrad);
+        let gx = op::build_checked(
+            ctx.graph(),
+            &[&ctx.input(0), &ctx.output_grad()],
+            PreprocessBinOpGrad,
+        );
         ctx.set_input_grads(vec![Some(gx), None]);
     }
+
+    fn schema(&self) -> OpSchema {
+        // Inputs: [gy, target_shape]
+        OpSchema::exactly(2)
+    }
 }
 
 impl<T: Float> op::Op<T> for AddOp {
+    // `AddOp` is built by operator-overload glue in another module, so its
+    // arity is validated here rather than at `op::build_checked` call sites.
     fn compute(&self, ctx: &mut crate::op::ComputeContext<T>) {
+        self.schema()
+            .check_arity(std::any::type_name::<Self>(), ctx.num_inputs());
         let ret = add_forward(&ctx.input(0), &ctx.input(1));
         ctx.append_output(Ok(ret));
     }
@@ -133,21 +208,114 @@ impl<T: Float> op::Op<T> for AddOp {
         let (gy1, gy2) = preprocess_gy(shape0, shape1, &ctx.output_grad(), ctx.graph());
         ctx.set_input_grads(vec![Some(gy1), Some(gy2)]);
     }
+
+    fn schema(&self) -> OpSchema {
+        OpSchema::exactly(2)
+    }
 }
 
 impl<T: Float> op::Op<T> for SubOp {
+    // `SubOp` is built by operator-overload glue in another module, so its
+    // arity is validated here rather than at `op::build_checked` call sites.
     fn compute(&self, ctx: &mut crate::op::ComputeContext<T>) {
+        self.schema()
+            .check_arity(std::any::type_name::<Self>(), ctx.num_inputs());
+        let ret = sub_forward(&ctx.input(0), &ctx.input(1));
+        ctx.append_output(Ok(ret));
+    }
+
+    fn grad(&self, ctx: &mut crate::op::GradientContext<T>) {
         let x0 = ctx.input(0);
         let x1 = ctx.input(1);
-        let shape0: &[usize] = x0.shape();
-        let ret = if shape0 == [] {
-            // is scalar
-            let x0_elem = x0[ndarray::IxDyn(&[])];
-            Ok(x1.map(move |&a| x0_elem - a))
+        let shape0 = &ctx.graph().shape(x0);
+        let shape1 = &ctx.graph().shape(x1);
+        let (gy1, gy2) = preprocess_gy(shape0, shape1, &ctx.output_grad(), ctx.graph());
+        ctx.set_input_grads(vec![Some(gy1), Some(ctx.graph().neg(&gy2))]);
+    }
+
+    fn schema(&self) -> OpSchema {
+        OpSchema::exactly(2)
+    }
+}
+
+/// Like `AddOp`, but writes the result into input 0's buffer instead of
+/// allocating a new one whenever the engine confirms that buffer isn't
+/// aliased/needed elsewhere. Still fully differentiable.
+pub struct InplaceAddOp;
+/// Like `SubOp`, but writes the result into input 0's buffer instead of
+/// allocating a new one whenever the engine confirms that buffer isn't
+/// aliased/needed elsewhere. Still fully differentiable.
+pub struct InplaceSubOp;
+
+impl<T: Float> op::Op<T> for InplaceAddOp {
+    // `InplaceAddOp` is built by `Graph::inplace_add` in another module, so
+    // its arity is validated here rather than at `op::build_checked` call
+    // sites.
+    fn compute(&self, ctx: &mut crate::op::ComputeContext<T>) {
+        let schema = self.schema();
+        schema.check_arity(std::any::type_name::<Self>(), ctx.num_inputs());
+        // Writing into input 0's buffer is only valid when the schema lists
+        // (0, 0) as an allowed alias and `x1` broadcasts into `x0`'s shape,
+        // since the result must fit in that buffer unchanged (e.g. `x0:
+        // [3,4] += x1: [4]` is fine; `x0: [4] += x1: [3,4]` isn't, and
+        // `zip_mut_with` alone doesn't broadcast at all).
+        let can_write_in_place = schema.allow_inplace.contains(&(0, 0))
+            && ctx.may_write_in_place(0)
+            && ctx.input(1).broadcast(ctx.input(0).shape()).is_some();
+        if can_write_in_place {
+            {
+                let x1 = ctx.input(1);
+                let mut x0 = ctx.input_mut(0);
+                ndarray::Zip::from(&mut x0)
+                    .and_broadcast(&x1)
+                    .for_each(|a, &b| *a = *a + b);
+            }
+            ctx.append_output_view(ctx.input(0));
         } else {
-            Ok(&x0 - &x1)
-        };
-        ctx.append_output(ret);
+            let ret = add_forward(&ctx.input(0), &ctx.input(1));
+            ctx.append_output(Ok(ret));
+        }
+    }
+
+    fn grad(&self, ctx: &mut crate::op::GradientContext<T>) {
+        let x0 = ctx.input(0);
+        let x1 = ctx.input(1);
+        let shape0 = &ctx.graph().shape(x0);
+        let shape1 = &ctx.graph().shape(x1);
+        let (gy1, gy2) = preprocess_gy(shape0, shape1, &ctx.output_grad(), ctx.graph());
+        ctx.set_input_grads(vec![Some(gy1), Some(gy2)]);
+    }
+
+    fn schema(&self) -> OpSchema {
+        OpSchema::exactly_with_inplace(2, &[(0, 0)])
+    }
+}
+
+impl<T: Float> op::Op<T> for InplaceSubOp {
+    // `InplaceSubOp` is built by `Graph::inplace_sub` in another module, so
+    // its arity is validated here rather than at `op::build_checked` call
+    // sites.
+    fn compute(&self, ctx: &mut crate::op::ComputeContext<T>) {
+        let schema = self.schema();
+        schema.check_arity(std::any::type_name::<Self>(), ctx.num_inputs());
+        // See `InplaceAddOp::compute`: in-place is only valid when the
+        // schema allows it and `x1` broadcasts into `x0`'s shape.
+        let can_write_in_place = schema.allow_inplace.contains(&(0, 0))
+            && ctx.may_write_in_place(0)
+            && ctx.input(1).broadcast(ctx.input(0).shape()).is_some();
+        if can_write_in_place {
+            {
+                let x1 = ctx.input(1);
+                let mut x0 = ctx.input_mut(0);
+                ndarray::Zip::from(&mut x0)
+                    .and_broadcast(&x1)
+                    .for_each(|a, &b| *a = *a - b);
+            }
+            ctx.append_output_view(ctx.input(0));
+        } else {
+            let ret = sub_forward(&ctx.input(0), &ctx.input(1));
+            ctx.append_output(Ok(ret));
+        }
     }
 
     fn grad(&self, ctx: &mut crate::op::GradientContext<T>) {
@@ -158,10 +326,18 @@ impl<T: Float> op::Op<T> for SubOp {
         let (gy1, gy2) = preprocess_gy(shape0, shape1, &ctx.output_grad(), ctx.graph());
         ctx.set_input_grads(vec![Some(gy1), Some(ctx.graph().neg(&gy2))]);
     }
+
+    fn schema(&self) -> OpSchema {
+        OpSchema::exactly_with_inplace(2, &[(0, 0)])
+    }
 }
 
 impl<T: Float> op::Op<T> for MulOp {
+    // `MulOp` is built by operator-overload glue in another module, so its
+    // arity is validated here rather than at `op::build_checked` call sites.
     fn compute(&self, ctx: &mut crate::op::ComputeContext<T>) {
+        self.schema()
+            .check_arity(std::any::type_name::<Self>(), ctx.num_inputs());
         let ret = mul_forward(&ctx.input(0), &ctx.input(1));
         ctx.append_output(Ok(ret));
     }
@@ -174,10 +350,18 @@ impl<T: Float> op::Op<T> for MulOp {
         let (gy1, gy2) = preprocess_gy(shape0, shape1, &ctx.output_grad(), ctx.graph());
         ctx.set_input_grads(vec![Some(gy1 * x1), Some(gy2 * x0)]);
     }
+
+    fn schema(&self) -> OpSchema {
+        OpSchema::exactly(2)
+    }
 }
 
 impl<T: Float> op::Op<T> for DivOp {
+    // `DivOp` is built by operator-overload glue in another module, so its
+    // arity is validated here rather than at `op::build_checked` call sites.
     fn compute(&self, ctx: &mut crate::op::ComputeContext<T>) {
+        self.schema()
+            .check_arity(std::any::type_name::<Self>(), ctx.num_inputs());
         let x0 = &ctx.input(0);
         let x1 = &ctx.input(1);
         let shape0: &[usize] = x0.shape();
@@ -211,24 +395,50 @@ impl<T: Float> op::Op<T> for DivOp {
             Some(scope.neg(x0) * scope.pow(x1, T::from(-2.).unwrap()) * gy2),
         ]);
     }
+
+    fn schema(&self) -> OpSchema {
+        OpSchema::exactly(2)
+    }
 }
 
 // Reduce gy if broadcast occurred in the forward path.
+//
+// `shape0` and `shape1` are frequently the same target shape (e.g. `x + x`,
+// or two operands that happen to already share a shape), so this memoizes
+// the `PreprocessBinOpGrad` node within this one call instead of building it
+// twice. The memo is local to a single `preprocess_gy` invocation and is
+// thrown away afterwards: caching it across independent calls would mean
+// two unrelated nodes sharing one `PreprocessBinOpGrad` tensor, and since
+// this is a lazily-evaluated graph, "replaying" a cache hit by rebinding
+// that tensor's `gy` input would silently repoint every earlier consumer of
+// the cached handle to whichever `gy` was bound last.
 fn preprocess_gy<'a, 'b: 'a, 'c, T: Float>(
     shape0: &Tensor<'a, 'b, T>,
     shape1: &Tensor<'a, 'b, T>,
     gy: &Tensor<'a, 'b, T>,
     c: &'b Graph<T>,
 ) -> (Tensor<'a, 'b, T>, Tensor<'a, 'b, T>) {
-    let gy0 = Tensor::builder()
-        .set_ro_inputs(&[gy, shape0])
-        .set_shape(shape0)
-        .build(c, PreprocessBinOpGrad);
-    let gy1 = Tensor::builder()
-        .set_ro_inputs(&[gy, shape1])
-        .set_shape(shape1)
-        .build(c, PreprocessBinOpGrad);
-    (gy0, gy1)
+    let mut built: Vec<(crate::ops::gy_dedup::GyDedupKey, Tensor<'a, 'b, T>)> =
+        Vec::with_capacity(2);
+    let mut build_or_reuse = |target_shape: &Tensor<'a, 'b, T>| {
+        let key = crate::ops::gy_dedup::GyDedupKey::new(
+            "PreprocessBinOpGrad",
+            vec![c.shape_vec(gy), c.shape_vec(target_shape)],
+            vec![c.requires_grad(gy), c.requires_grad(target_shape)],
+        );
+        if let Some((_, cached)) = built.iter().find(|(k, _)| *k == key) {
+            return cached.clone();
+        }
+        <PreprocessBinOpGrad as op::Op<T>>::schema(&PreprocessBinOpGrad)
+            .check_arity(std::any::type_name::<PreprocessBinOpGrad>(), 2);
+        let node = Tensor::builder()
+            .set_ro_inputs(&[gy, target_shape])
+            .set_shape(target_shape)
+            .build(c, PreprocessBinOpGrad);
+        built.push((key, node.clone()));
+        node
+    };
+    (build_or_reuse(shape0), build_or_reuse(shape1))
 }
 
 macro_rules! impl_bin_op_forward {
@@ -267,3 +477,42 @@ macro_rules! impl_bin_op_forward {
 
 impl_bin_op_forward!(add_forward, +);
 impl_bin_op_forward!(mul_forward, *);
+
+// Unlike `add_forward`/`mul_forward`, subtraction isn't commutative, so only
+// `x0` gets a scalar fast path: a scalar `x1` falls through to ndarray's own
+// (non-broadcasting-aware-of-scalars) `-`, matching the original `SubOp`.
+fn sub_forward<'v, T: Float>(x0: &NdArrayView<'v, T>, x1: &NdArrayView<'v, T>) -> NdArray<T> {
+    let shape0: &[usize] = x0.shape();
+    if shape0 == [] {
+        // x0 is a scalar
+        let x0_elem = x0[ndarray::IxDyn(&[])];
+        x1.map(move |&a| x0_elem - a)
+    } else {
+        x0 - x1
+    }
+}
+
+#[cfg(test)]
+mod sub_forward_tests {
+    use super::*;
+    use ndarray::Array;
+
+    #[test]
+    fn elementwise_subtract_same_shape() {
+        let x0 = Array::from_shape_vec(ndarray::IxDyn(&[2]), vec![5.0, 7.0]).unwrap();
+        let x1 = Array::from_shape_vec(ndarray::IxDyn(&[2]), vec![1.0, 2.0]).unwrap();
+        let ret = sub_forward(&x0.view(), &x1.view());
+        assert_eq!(ret, Array::from_shape_vec(ndarray::IxDyn(&[2]), vec![4.0, 5.0]).unwrap());
+    }
+
+    #[test]
+    fn scalar_x0_broadcasts_over_x1() {
+        let x0 = Array::from_shape_vec(ndarray::IxDyn(&[]), vec![10.0]).unwrap();
+        let x1 = Array::from_shape_vec(ndarray::IxDyn(&[3]), vec![1.0, 2.0, 3.0]).unwrap();
+        let ret = sub_forward(&x0.view(), &x1.view());
+        assert_eq!(
+            ret,
+            Array::from_shape_vec(ndarray::IxDyn(&[3]), vec![9.0, 8.0, 7.0]).unwrap()
+        );
+    }
+}
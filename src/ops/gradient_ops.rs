@@ -1,15 +1,204 @@
+use super::schema::OpSchema;
 use crate::op;
+use crate::tensor::Tensor;
 use crate::Float;
+use crate::Graph;
+use ndarray;
 
 pub struct StopGradient;
 
+/// Passes the gradient flowing through this node where the forward value
+/// lies in `[min, max]` and zeroes it elsewhere (a straight-through clamp);
+/// the forward value is passed through unchanged.
+pub struct ClipGradByValue<T: Float> {
+    pub min: T,
+    pub max: T,
+}
+
+// Inputs: [output_grad, forward_x]
+struct ClipGradByValueGrad<T: Float> {
+    min: T,
+    max: T,
+}
+
+/// Rescales the gradient flowing through this node so its L2 norm never
+/// exceeds `max_norm`; the forward value is passed through unchanged.
+pub struct ClipGradByNorm<T: Float> {
+    pub max_norm: T,
+}
+
+// Inputs: [output_grad]
+struct ClipGradByNormGrad<T: Float> {
+    max_norm: T,
+}
+
 impl<T: Float> op::Op<T> for StopGradient {
+    // `StopGradient` is built by `Graph::stop_gradient` in another module,
+    // so its arity is validated here rather than at `op::build_checked` call
+    // sites.
+    fn compute(&self, ctx: &mut crate::op::ComputeContext<T>) {
+        self.schema()
+            .check_arity(std::any::type_name::<Self>(), ctx.num_inputs());
+        let ret = ctx.input(0);
+        ctx.append_output_view(ret);
+    }
+
+    fn grad(&self, ctx: &mut crate::op::GradientContext<T>) {
+        ctx.append_input_grad(None);
+    }
+
+    fn schema(&self) -> OpSchema {
+        OpSchema::exactly(1)
+    }
+}
+
+impl<T: Float> op::Op<T> for ClipGradByValue<T> {
+    // `ClipGradByValue` is built by `Graph::clip_grad_by_value` below via
+    // `op::build_checked`, so arity is validated there rather than here.
+    fn compute(&self, ctx: &mut crate::op::ComputeContext<T>) {
+        // Identity in the forward pass; the clamp is applied in `grad`.
+        let ret = ctx.input(0);
+        ctx.append_output_view(ret);
+    }
+
+    fn grad(&self, ctx: &mut crate::op::GradientContext<T>) {
+        let gx = op::build_checked(
+            ctx.graph(),
+            &[&ctx.output_grad(), &ctx.input(0)],
+            ClipGradByValueGrad {
+                min: self.min,
+                max: self.max,
+            },
+        );
+        ctx.append_input_grad(Some(gx));
+    }
+
+    fn schema(&self) -> OpSchema {
+        OpSchema::exactly(1)
+    }
+}
+
+impl<T: Float> op::Op<T> for ClipGradByValueGrad<T> {
+    // Arity is validated by the caller at construction time
+    // (`op::build_checked` in `ClipGradByValue::grad`), since this op is
+    // only ever built within this crate.
+    fn compute(&self, ctx: &mut crate::op::ComputeContext<T>) {
+        let gy = ctx.input(0);
+        let x = ctx.input(1);
+        let (min, max) = (self.min, self.max);
+        let ret = ndarray::Zip::from(&gy)
+            .and(&x)
+            .map_collect(|&g, &xi| if xi >= min && xi <= max { g } else { T::zero() });
+        ctx.append_output(Ok(ret));
+    }
+
+    fn grad(&self, ctx: &mut crate::op::GradientContext<T>) {
+        // Second-order gradients through the clamp mask are not supported.
+        ctx.set_input_grads(vec![None, None]);
+    }
+
+    fn schema(&self) -> OpSchema {
+        OpSchema::exactly(2)
+    }
+}
+
+impl<T: Float> op::Op<T> for ClipGradByNorm<T> {
+    // `ClipGradByNorm` is built by `Graph::clip_grad_by_norm` below via
+    // `op::build_checked`, so arity is validated there rather than here.
     fn compute(&self, ctx: &mut crate::op::ComputeContext<T>) {
+        // Identity in the forward pass; the rescale is applied in `grad`.
         let ret = ctx.input(0);
         ctx.append_output_view(ret);
     }
 
+    fn grad(&self, ctx: &mut crate::op::GradientContext<T>) {
+        let gx = op::build_checked(
+            ctx.graph(),
+            &[&ctx.output_grad()],
+            ClipGradByNormGrad {
+                max_norm: self.max_norm,
+            },
+        );
+        ctx.append_input_grad(Some(gx));
+    }
+
+    fn schema(&self) -> OpSchema {
+        OpSchema::exactly(1)
+    }
+}
+
+impl<T: Float> op::Op<T> for ClipGradByNormGrad<T> {
+    // Arity is validated by the caller at construction time
+    // (`op::build_checked` in `ClipGradByNorm::grad`), since this op is only
+    // ever built within this crate.
+    fn compute(&self, ctx: &mut crate::op::ComputeContext<T>) {
+        let gy = ctx.input(0);
+        let scale = clip_by_norm_scale(gy.fold(T::zero(), |acc, &g| acc + g * g).sqrt(), self.max_norm);
+        let ret = gy.mapv(|g| g * scale);
+        ctx.append_output(Ok(ret));
+    }
+
     fn grad(&self, ctx: &mut crate::op::GradientContext<T>) {
         ctx.append_input_grad(None);
     }
+
+    fn schema(&self) -> OpSchema {
+        OpSchema::exactly(1)
+    }
+}
+
+/// `min(1, max_norm / (norm + eps))`: the factor `ClipGradByNormGrad` scales
+/// its gradient by, so the rescaled gradient's L2 norm never exceeds
+/// `max_norm` while never being scaled up when it's already within bounds.
+fn clip_by_norm_scale<T: Float>(norm: T, max_norm: T) -> T {
+    T::min(T::one(), max_norm / (norm + T::epsilon()))
+}
+
+#[cfg(test)]
+mod clip_by_norm_scale_tests {
+    use super::*;
+
+    #[test]
+    fn leaves_norm_within_bound_unscaled() {
+        assert_eq!(clip_by_norm_scale(1.0_f64, 5.0), 1.0);
+    }
+
+    #[test]
+    fn shrinks_norm_exceeding_bound() {
+        let scale = clip_by_norm_scale(10.0_f64, 5.0);
+        assert!((scale - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn never_scales_up() {
+        // A tiny norm should still be scaled by at most 1, never amplified.
+        let scale = clip_by_norm_scale(0.001_f64, 5.0);
+        assert!(scale <= 1.0);
+    }
+}
+
+impl<T: Float> Graph<T> {
+    /// Inserts a node that, during backprop, passes gradients flowing through
+    /// `x` where `x`'s value lies in `[min, max]` and zeroes them elsewhere,
+    /// passing the forward value through unchanged. Mirrors
+    /// [`Graph::stop_gradient`].
+    pub fn clip_grad_by_value<'graph>(
+        &'graph self,
+        x: &Tensor<'_, 'graph, T>,
+        min: T,
+        max: T,
+    ) -> Tensor<'_, 'graph, T> {
+        op::build_checked(self, &[x], ClipGradByValue { min, max })
+    }
+
+    /// Inserts a node that rescales gradients flowing through `x` so their
+    /// L2 norm never exceeds `max_norm` during backprop, passing the
+    /// forward value through unchanged. Mirrors [`Graph::stop_gradient`].
+    pub fn clip_grad_by_norm<'graph>(
+        &'graph self,
+        x: &Tensor<'_, 'graph, T>,
+        max_norm: T,
+    ) -> Tensor<'_, 'graph, T> {
+        op::build_checked(self, &[x], ClipGradByNorm { max_norm })
+    }
 }
@@ -0,0 +1,68 @@
+/// Identifies a `PreprocessBinOpGrad` node to be built within a single
+/// `preprocess_gy` call: the constructing op's name, each input's shape, and
+/// whether each input requires a gradient.
+///
+/// This is common-subexpression elimination scoped to that one call, not a
+/// cache: `shape0` and `shape1` are frequently the same target shape (e.g.
+/// `x + x`), so two equal keys within a single `preprocess_gy` invocation
+/// mean the second request can reuse the first's freshly-built node instead
+/// of building an identical one again. `requires_grad` must be part of the
+/// key: a node built when only one input needed a gradient must never be
+/// handed back for a request where both do.
+///
+/// It is never safe to keep these keys, or the nodes they map to, around
+/// *across* independent `preprocess_gy` calls: the graph this crate builds
+/// is lazily evaluated, so two unrelated call sites that happen to share a
+/// key still need their own distinct node — reusing one node across them
+/// would mean later rebinding its input to a different `gy`, silently
+/// repointing every earlier consumer of that node to the wrong gradient.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GyDedupKey {
+    op_name: &'static str,
+    input_shapes: Vec<Vec<usize>>,
+    requires_grad: Vec<bool>,
+}
+
+impl GyDedupKey {
+    pub fn new(
+        op_name: &'static str,
+        input_shapes: Vec<Vec<usize>>,
+        requires_grad: Vec<bool>,
+    ) -> Self {
+        GyDedupKey {
+            op_name,
+            input_shapes,
+            requires_grad,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_shapes_and_requires_grad_produce_equal_keys() {
+        let a = GyDedupKey::new("PreprocessBinOpGrad", vec![vec![2, 3], vec![3]], vec![true, false]);
+        let b = GyDedupKey::new("PreprocessBinOpGrad", vec![vec![2, 3], vec![3]], vec![true, false]);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn differing_requires_grad_produces_distinct_keys() {
+        // A node built when only one input needed a gradient must never be
+        // treated as equal to one where both do.
+        let one_needs_grad =
+            GyDedupKey::new("PreprocessBinOpGrad", vec![vec![2, 3], vec![3]], vec![true, false]);
+        let both_need_grad =
+            GyDedupKey::new("PreprocessBinOpGrad", vec![vec![2, 3], vec![3]], vec![true, true]);
+        assert_ne!(one_needs_grad, both_need_grad);
+    }
+
+    #[test]
+    fn differing_shapes_produce_distinct_keys() {
+        let a = GyDedupKey::new("PreprocessBinOpGrad", vec![vec![2, 3], vec![3]], vec![true, true]);
+        let b = GyDedupKey::new("PreprocessBinOpGrad", vec![vec![2, 4], vec![4]], vec![true, true]);
+        assert_ne!(a, b);
+    }
+}
@@ -0,0 +1,4 @@
+pub mod binary_ops;
+pub mod gradient_ops;
+pub mod gy_dedup;
+pub mod schema;
@@ -0,0 +1,116 @@
+/// Declares an op's input arity and which `(input, output)` buffer pairs may
+/// safely alias each other during in-place execution.
+///
+/// Backs the `op::Op::schema` trait method. Op-building call sites that go
+/// through `op::build_checked` validate the wired input count against
+/// `num_inputs` at graph-construction time; ops built without that helper
+/// (e.g. by operator-overload glue) validate it themselves at the top of
+/// `compute` instead.
+/// Either way, a wiring mistake fails with a clear diagnostic instead of the
+/// out-of-bounds panic `ctx.input(i)` would otherwise raise.
+/// `allow_inplace` tells ops which `(input, output)` buffer pairs they are
+/// permitted to alias; `InplaceAddOp`/`InplaceSubOp` consult it before
+/// writing into an input's buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct OpSchema {
+    /// Inclusive `(min, max)` number of inputs this op accepts.
+    pub num_inputs: (usize, usize),
+    /// `(input_idx, output_idx)` pairs the op allows the engine to alias.
+    pub allow_inplace: &'static [(usize, usize)],
+}
+
+impl Default for OpSchema {
+    /// Unconstrained: any arity, no in-place aliasing. Ops that don't
+    /// override `Op::schema` get this, matching their behavior from before
+    /// `OpSchema` existed.
+    fn default() -> Self {
+        OpSchema {
+            num_inputs: (0, usize::MAX),
+            allow_inplace: &[],
+        }
+    }
+}
+
+impl OpSchema {
+    /// Schema for an op that takes exactly `n` inputs and never aliases buffers.
+    pub const fn exactly(n: usize) -> Self {
+        OpSchema {
+            num_inputs: (n, n),
+            allow_inplace: &[],
+        }
+    }
+
+    /// Schema for an op that takes exactly `n` inputs and aliases the given
+    /// `(input_idx, output_idx)` pairs in place.
+    pub const fn exactly_with_inplace(n: usize, allow_inplace: &'static [(usize, usize)]) -> Self {
+        OpSchema {
+            num_inputs: (n, n),
+            allow_inplace,
+        }
+    }
+
+    /// Checks that `given` inputs satisfy this schema's arity, returning a
+    /// human-readable diagnostic naming the op on mismatch.
+    pub fn validate_arity(&self, op_name: &str, given: usize) -> Result<(), String> {
+        let (min, max) = self.num_inputs;
+        if given < min || given > max {
+            let arity = if min == max {
+                format!("exactly {}", min)
+            } else {
+                format!("{}..={}", min, max)
+            };
+            Err(format!(
+                "{} expects {} input(s), but {} were given",
+                op_name, arity, given
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Same as [`Self::validate_arity`], but panics with the diagnostic
+    /// instead of returning it. Ops call this at the top of `compute` so a
+    /// wiring mistake fails with a clear message instead of the out-of-bounds
+    /// panic `ctx.input(i)` would otherwise raise.
+    pub fn check_arity(&self, op_name: &str, given: usize) {
+        if let Err(e) = self.validate_arity(op_name, given) {
+            panic!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exactly_accepts_matching_arity() {
+        assert!(OpSchema::exactly(2).validate_arity("AddOp", 2).is_ok());
+    }
+
+    #[test]
+    fn exactly_rejects_too_few_inputs() {
+        let err = OpSchema::exactly(2).validate_arity("AddOp", 1).unwrap_err();
+        assert!(err.contains("AddOp"));
+        assert!(err.contains("exactly 2"));
+    }
+
+    #[test]
+    fn exactly_rejects_too_many_inputs() {
+        assert!(OpSchema::exactly(1).validate_arity("StopGradient", 2).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "exactly 1")]
+    fn check_arity_panics_on_mismatch() {
+        OpSchema::exactly(1).check_arity("StopGradient", 0);
+    }
+
+    #[test]
+    fn default_is_unconstrained() {
+        let schema = OpSchema::default();
+        assert!(schema.validate_arity("AnyOp", 0).is_ok());
+        assert!(schema.validate_arity("AnyOp", 7).is_ok());
+        assert!(schema.allow_inplace.is_empty());
+    }
+}